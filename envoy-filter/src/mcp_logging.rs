@@ -1,37 +1,348 @@
 use envoy_proxy_dynamic_modules_rust_sdk::*;
 use envoy_proxy_dynamic_modules_rust_sdk::{EnvoyBuffer};
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// A single prefix-routed MCP backend.
+///
+/// Tool names are matched against `prefix` (longest match wins when more than
+/// one route could apply) and, once matched, route to `backend`, which is
+/// used both as the `x-mcp-server` header value and as the key into the
+/// gateway's session lookup response. `session_prefix` is the prefix this
+/// backend puts in front of its session ids, which is stripped back off on
+/// the way to the client in `on_response_headers`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteConfig {
+    prefix: String,
+    backend: String,
+    session_prefix: String,
+}
 
 /// Configuration for the body-based routing filter.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FilterConfig {
     #[serde(default)]
     debug: bool,
+    #[serde(default = "default_routes")]
+    routes: Vec<RouteConfig>,
+    /// How long a cached gateway-session -> backend-sessions mapping stays
+    /// fresh before a `tools/call` has to re-resolve it via callout.
+    #[serde(default = "default_session_cache_ttl_secs")]
+    session_cache_ttl_secs: u64,
+    /// Shared across every per-request `Filter` the SDK constructs from this
+    /// config, so a session resolved once is reused without a callout.
+    #[serde(skip, default = "SessionCache::new")]
+    session_cache: SessionCache,
+    /// When set, caps `tools/call` throughput with a token bucket. Absent by
+    /// default so existing deployments are unaffected.
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(skip, default = "RateLimiter::new")]
+    rate_limiter: RateLimiter,
+    /// Caps how large a buffered request body may grow before it's rejected
+    /// outright. `None` preserves the historical unbounded-buffering behavior.
+    #[serde(default)]
+    max_body_bytes: Option<u64>,
+    /// Timeout in milliseconds for the gateway session-lookup callout.
+    #[serde(default = "default_callout_timeout_ms")]
+    callout_timeout_ms: u32,
+    /// What to do when that callout times out or fails.
+    #[serde(default)]
+    session_lookup_fail_mode: SessionLookupFailMode,
+    /// Correlation header name threaded through the gateway callout and
+    /// backend hops, and echoed back on the response.
+    #[serde(default = "default_request_id_header")]
+    request_id_header: String,
+    /// When `true`, always mint a fresh UUIDv4 instead of trusting an
+    /// inbound `request_id_header` value.
+    #[serde(default)]
+    always_regenerate_request_id: bool,
+    /// How to handle a JSON-RPC batch that mixes backends (or mixes
+    /// `tools/call` with `initialize`/`tools/list`): when `true`, send the
+    /// whole batch to the gateway unmodified; when `false` (the default),
+    /// reject it with a JSON-RPC error since the gateway has no way to
+    /// route a single batch to more than one backend.
+    #[serde(default)]
+    allow_mixed_batch_to_gateway: bool,
+}
+
+fn default_request_id_header() -> String {
+    "x-mcp-request-id".to_string()
+}
+
+fn default_session_cache_ttl_secs() -> u64 {
+    300
+}
+
+/// A resolved gateway session -> per-backend session ids mapping, plus when
+/// it was cached, so entries can be expired on a configurable TTL.
+#[derive(Clone, Debug)]
+struct CachedSession {
+    sessions: HashMap<String, String>,
+    cached_at: Instant,
+}
+
+/// Shared cache of gateway `mcp-session-id` -> resolved backend session ids.
+///
+/// Envoy constructs a new `Filter` per request but reuses the same
+/// `FilterConfig`, so this wraps the map in an `Arc` and is cloned (cheaply)
+/// into each `Filter` instance to avoid a `send_http_callout` on every
+/// `tools/call` once a session has been resolved once.
+#[derive(Clone, Debug)]
+struct SessionCache(Arc<DashMap<String, CachedSession>>);
+
+impl SessionCache {
+    fn new() -> Self {
+        SessionCache(Arc::new(DashMap::new()))
+    }
+
+    fn get(&self, gateway_session: &str, ttl_secs: u64) -> Option<HashMap<String, String>> {
+        let entry = self.0.get(gateway_session)?;
+        if entry.cached_at.elapsed().as_secs() >= ttl_secs {
+            return None;
+        }
+        Some(entry.sessions.clone())
+    }
+
+    fn insert(&self, gateway_session: String, sessions: HashMap<String, String>) {
+        self.0.insert(gateway_session, CachedSession { sessions, cached_at: Instant::now() });
+    }
+}
+
+/// What a rate-limit bucket is keyed by.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RateLimitKeyBy {
+    /// One bucket per `mcp-session-id`.
+    Session,
+    /// One bucket per (stripped) tool name.
+    Tool,
 }
 
-/// Response from the gateway session lookup endpoint
+/// Configuration for the optional per-session/per-tool local rate limiter.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RateLimitConfig {
+    capacity: f64,
+    refill_per_sec: f64,
+    #[serde(default = "default_rate_limit_key_by")]
+    key_by: RateLimitKeyBy,
+}
+
+fn default_rate_limit_key_by() -> RateLimitKeyBy {
+    RateLimitKeyBy::Session
+}
+
+/// A single token bucket: refilled lazily on each check rather than on a timer.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Shared token-bucket map, keyed by session id or tool name depending on
+/// `RateLimitConfig::key_by`. Cloned (cheaply, via `Arc`) into every
+/// per-request `Filter` built from the same `FilterConfig`, mirroring
+/// `SessionCache`.
+#[derive(Clone, Debug)]
+struct RateLimiter(Arc<DashMap<String, TokenBucket>>);
+
+impl std::fmt::Debug for TokenBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenBucket").field("tokens", &self.tokens).finish()
+    }
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter(Arc::new(DashMap::new()))
+    }
+
+    /// Refills `key`'s bucket based on elapsed time, then attempts to take
+    /// one token. Returns `true` if the request may proceed.
+    fn try_acquire(&self, key: &str, capacity: f64, refill_per_sec: f64) -> bool {
+        self.try_acquire_n(key, capacity, refill_per_sec, 1.0)
+    }
+
+    /// Like `try_acquire`, but takes `count` tokens at once (or none, if
+    /// fewer than `count` are available) rather than always one. Used so a
+    /// JSON-RPC batch of N `tools/call` costs N tokens instead of 1,
+    /// matching the per-call cost of sending them as N separate requests.
+    fn try_acquire_n(&self, key: &str, capacity: f64, refill_per_sec: f64, count: f64) -> bool {
+        let mut bucket = self
+            .0
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket { tokens: capacity, last_refill: Instant::now() });
+
+        let now = Instant::now();
+        let elapsed_secs = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed_secs * refill_per_sec).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= count {
+            bucket.tokens -= count;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do when the gateway session-lookup callout times out or otherwise
+/// fails.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum SessionLookupFailMode {
+    /// Synthesize a `{session_prefix}{gateway}` id and let the request
+    /// through optimistically. This is the historical behavior.
+    #[default]
+    Fallback,
+    /// Reject the request with a JSON-RPC error rather than routing it to a
+    /// fabricated backend session.
+    Strict,
+}
+
+fn default_callout_timeout_ms() -> u32 {
+    5000
+}
+
+/// The historical server1/server2 routing table, kept as the default so
+/// existing deployments that don't set `routes` keep working unchanged.
+fn default_routes() -> Vec<RouteConfig> {
+    vec![
+        RouteConfig {
+            prefix: "server1-".to_string(),
+            backend: "server1".to_string(),
+            session_prefix: "server1-session-".to_string(),
+        },
+        RouteConfig {
+            prefix: "server2-".to_string(),
+            backend: "server2".to_string(),
+            session_prefix: "server2-session-".to_string(),
+        },
+    ]
+}
+
+/// Response from the gateway session lookup endpoint.
+///
+/// Prior to the configurable routing table, this response carried flat
+/// `server1_session_id`/`server2_session_id` fields instead of a `sessions`
+/// map. `server1_session_id`/`server2_session_id` are still accepted and
+/// folded into `sessions` by `normalize_legacy_fields` (keyed by the
+/// historical `"server1"`/`"server2"` backend names), so an un-upgraded
+/// gateway keeps working unchanged. New gateways, and any gateway serving a
+/// routes config with more than those two backends, should emit
+/// `sessions: {"<backend>": "<session id>"}` (keyed by `RouteConfig::backend`)
+/// directly.
 #[derive(Deserialize, Debug)]
 struct SessionLookupResponse {
-    server1_session_id: String,
-    server2_session_id: String,
+    /// Backend name (`RouteConfig::backend`) -> resolved backend session id.
+    #[serde(default)]
+    sessions: HashMap<String, String>,
     found: bool,
+    /// Legacy flat fields from before the routing table was configurable;
+    /// folded into `sessions` by `normalize_legacy_fields`.
+    #[serde(default)]
+    server1_session_id: Option<String>,
+    #[serde(default)]
+    server2_session_id: Option<String>,
+}
+
+impl SessionLookupResponse {
+    /// Folds the legacy flat `server1_session_id`/`server2_session_id`
+    /// fields into `sessions` when present, so a gateway that hasn't been
+    /// upgraded to the `sessions` map wire format keeps working.
+    fn normalize_legacy_fields(&mut self) {
+        if let Some(id) = self.server1_session_id.take() {
+            self.sessions.entry("server1".to_string()).or_insert(id);
+        }
+        if let Some(id) = self.server2_session_id.take() {
+            self.sessions.entry("server2".to_string()).or_insert(id);
+        }
+    }
+}
+
+/// Finds the route whose prefix matches `tool_name`, preferring the longest
+/// prefix when more than one route could apply.
+fn match_route<'a>(routes: &'a [RouteConfig], tool_name: &str) -> Option<&'a RouteConfig> {
+    routes
+        .iter()
+        .filter(|route| tool_name.starts_with(route.prefix.as_str()))
+        .max_by_key(|route| route.prefix.len())
+}
+
+/// Decides whether a JSON-RPC batch can be routed to a single backend: every
+/// element must be a `tools/call` whose tool name matches a configured route,
+/// and all matched routes must share the same `backend`. Returns the shared
+/// route on success, `None` if the batch is empty, mixes methods, or mixes
+/// backends.
+fn resolve_batch_route(routes: &[RouteConfig], elements: &[serde_json::Value]) -> Option<RouteConfig> {
+    let mut matched_route: Option<RouteConfig> = None;
+
+    for element in elements {
+        let method = element.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        if method != "tools/call" {
+            return None;
+        }
+
+        let tool_name = element.get("params").and_then(|p| p.get("name")).and_then(|n| n.as_str()).unwrap_or("");
+        match match_route(routes, tool_name) {
+            Some(route) if matched_route.as_ref().map_or(true, |r| r.backend == route.backend) => {
+                matched_route = Some(route.clone());
+            }
+            _ => return None,
+        }
+    }
+
+    matched_route
 }
 
 impl FilterConfig {
     /// Creates a new FilterConfig from JSON configuration.
     pub fn new(filter_config: &str) -> Self {
         if filter_config.trim().is_empty() {
-            FilterConfig { debug: false }
+            Self::default_config()
         } else {
             serde_json::from_str::<FilterConfig>(filter_config)
-                .unwrap_or_else(|_| FilterConfig { debug: false })
+                .unwrap_or_else(|_| Self::default_config())
+        }
+    }
+
+    fn default_config() -> Self {
+        FilterConfig {
+            debug: false,
+            routes: default_routes(),
+            session_cache_ttl_secs: default_session_cache_ttl_secs(),
+            session_cache: SessionCache::new(),
+            rate_limit: None,
+            rate_limiter: RateLimiter::new(),
+            max_body_bytes: None,
+            callout_timeout_ms: default_callout_timeout_ms(),
+            session_lookup_fail_mode: SessionLookupFailMode::default(),
+            request_id_header: default_request_id_header(),
+            always_regenerate_request_id: false,
+            allow_mixed_batch_to_gateway: false,
         }
     }
 }
 
 impl<EC: EnvoyHttpFilterConfig, EHF: EnvoyHttpFilter> HttpFilterConfig<EC, EHF> for FilterConfig {
     fn new_http_filter(&mut self, _envoy: &mut EC) -> Box<dyn HttpFilter<EHF>> {
-        Box::new(Filter::new())
+        Box::new(Filter::new(
+            self.routes.clone(),
+            self.session_cache_ttl_secs,
+            self.session_cache.clone(),
+            self.rate_limit.clone(),
+            self.rate_limiter.clone(),
+            self.max_body_bytes,
+            self.callout_timeout_ms,
+            self.session_lookup_fail_mode,
+            self.request_id_header.clone(),
+            self.always_regenerate_request_id,
+            self.allow_mixed_batch_to_gateway,
+        ))
     }
 }
 
@@ -50,21 +361,49 @@ fn find_header_value(headers: &[(EnvoyBuffer, EnvoyBuffer)], name: &str) -> Stri
 }
 
 /// Body-based routing filter that analyzes request bodies and sets routing headers.
-/// 
+///
 /// MEMORY CONSIDERATIONS:
 /// - Buffers complete request bodies in memory during analysis
 /// - Memory usage scales with request body size
 /// - Consider implementing body size limits for production use
-/// 
+/// - `max_body_bytes` sets such a limit when configured; it is unset by default
+///
 /// LATENCY CONSIDERATIONS:
 /// - Pauses request processing until complete body is available
 /// - JSON parsing adds computational overhead
 /// - Route cache clearing forces re-evaluation (small cost)
 pub struct Filter {
+    // The prefix -> backend routing table, cloned from FilterConfig when this
+    // per-request Filter was constructed.
+    routes: Vec<RouteConfig>,
+    // Shared gateway-session -> backend-sessions cache (same Arc across every
+    // Filter built from the same FilterConfig).
+    session_cache: SessionCache,
+    session_cache_ttl_secs: u64,
+    // Optional local rate limiter, shared (same Arc) across every Filter
+    // built from the same FilterConfig.
+    rate_limit: Option<RateLimitConfig>,
+    rate_limiter: RateLimiter,
+    // Maximum buffered request body size before on_request_body rejects it.
+    max_body_bytes: Option<u64>,
+    callout_timeout_ms: u32,
+    session_lookup_fail_mode: SessionLookupFailMode,
+    request_id_header: String,
+    always_regenerate_request_id: bool,
+    allow_mixed_batch_to_gateway: bool,
+    // The correlation id for the request currently being processed, set in
+    // on_request_headers and threaded through the callout and response.
+    request_id: Option<String>,
     // Store the session lookup response while processing
     pending_session_lookup: Option<SessionLookupResponse>,
-    // Store the routing decision while waiting for session lookup
-    pending_route_decision: Option<String>,
+    // Store the matched route while waiting for session lookup
+    pending_route: Option<RouteConfig>,
+    // Store the gateway session id a pending callout is resolving, so the
+    // result can be written back into the cache once it arrives.
+    pending_gateway_session: Option<String>,
+    // Store the JSON-RPC `id` of the in-flight request, so a strict-mode
+    // session-lookup failure can echo it back in its error response.
+    pending_request_id: Option<serde_json::Value>,
     // Store the stripped tool name for tools/call
     stripped_tool_name: Option<String>,
     // Store the current request body for modification
@@ -72,31 +411,124 @@ pub struct Filter {
 }
 
 impl Filter {
-    pub fn new() -> Self {
+    pub fn new(
+        routes: Vec<RouteConfig>,
+        session_cache_ttl_secs: u64,
+        session_cache: SessionCache,
+        rate_limit: Option<RateLimitConfig>,
+        rate_limiter: RateLimiter,
+        max_body_bytes: Option<u64>,
+        callout_timeout_ms: u32,
+        session_lookup_fail_mode: SessionLookupFailMode,
+        request_id_header: String,
+        always_regenerate_request_id: bool,
+        allow_mixed_batch_to_gateway: bool,
+    ) -> Self {
         Filter {
+            routes,
+            session_cache,
+            session_cache_ttl_secs,
+            rate_limit,
+            rate_limiter,
+            max_body_bytes,
+            callout_timeout_ms,
+            session_lookup_fail_mode,
+            request_id_header,
+            always_regenerate_request_id,
+            allow_mixed_batch_to_gateway,
+            request_id: None,
             pending_session_lookup: None,
-            pending_route_decision: None,
+            pending_route: None,
+            pending_gateway_session: None,
+            pending_request_id: None,
             stripped_tool_name: None,
             current_request_body: None,
         }
     }
 
-    // Helper method to handle fallback session creation
-    fn handle_fallback_session<EHF: EnvoyHttpFilter>(&mut self, envoy_filter: &mut EHF, route_decision: &str) {
+    // Sends a direct local reply, bypassing the upstream entirely.
+    fn send_local_reply<EHF: EnvoyHttpFilter>(&self, envoy_filter: &mut EHF, status_code: u32, body: &[u8]) {
+        let content_length = body.len().to_string();
+        let headers: Vec<(&str, &[u8])> = vec![
+            ("content-type", b"application/json".as_slice()),
+            ("content-length", content_length.as_bytes()),
+        ];
+        envoy_filter.send_response(status_code, headers, Some(body));
+    }
+
+    // Applies `session_lookup_fail_mode` when a route's backend session
+    // couldn't be resolved, for `reason` (used in the strict-mode JSON-RPC
+    // error so operators see why, rather than a one-size-fits-all message).
+    // Returns `true` if the request was rejected with a local reply, `false`
+    // if the fabricated fallback session headers were applied instead and
+    // the caller should resume routing as normal.
+    fn apply_fallback_session<EHF: EnvoyHttpFilter>(
+        &mut self,
+        envoy_filter: &mut EHF,
+        route: &RouteConfig,
+        reason: &str,
+    ) -> bool {
+        if self.session_lookup_fail_mode == SessionLookupFailMode::Strict {
+            eprintln!("[MCP_FILTER] Session lookup failed in strict mode ({}), rejecting request", reason);
+            let id = self.pending_request_id.take().unwrap_or(serde_json::Value::Null);
+            let error_body = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": -32000,
+                    "message": format!("session resolution failed: {}", reason),
+                },
+            })
+            .to_string();
+            self.send_local_reply(envoy_filter, 504, error_body.as_bytes());
+            return true;
+        }
+
         let headers = envoy_filter.get_request_headers();
         let gateway_session = find_header_value(&headers, "mcp-session-id");
-        let backend_session = format!("{}-session-{}", route_decision, gateway_session);
+        let backend_session = format!("{}{}", route.session_prefix, gateway_session);
         envoy_filter.set_request_header("mcp-session-id", backend_session.as_bytes());
-        envoy_filter.set_request_header("x-mcp-server", route_decision.as_bytes());
-        
+        envoy_filter.set_request_header("x-mcp-server", route.backend.as_bytes());
         envoy_filter.clear_route_cache();
-        envoy_filter.continue_decoding();
-        
-        // Clear the pending state
-        self.pending_route_decision = None;
+        false
+    }
+
+    // Helper method to handle fallback session creation once a callout has
+    // already completed (or failed) asynchronously: applies the fallback
+    // (or rejects, in strict mode) and resumes decoding if the request
+    // wasn't rejected, then clears the pending callout state either way.
+    fn handle_fallback_session<EHF: EnvoyHttpFilter>(&mut self, envoy_filter: &mut EHF, route: &RouteConfig, reason: &str) {
+        let rejected = self.apply_fallback_session(envoy_filter, route, reason);
+        if !rejected {
+            envoy_filter.continue_decoding();
+        }
+
+        self.pending_route = None;
+        self.pending_gateway_session = None;
+        self.pending_request_id = None;
         self.stripped_tool_name = None;
     }
 
+    // Best-effort extraction of the JSON-RPC `id` from whatever has been
+    // buffered so far, so a request-too-large error can still echo it back.
+    // Falls back to `null` when the partial body isn't valid JSON yet.
+    fn extract_partial_request_id<EHF: EnvoyHttpFilter>(&self, envoy_filter: &mut EHF) -> String {
+        if let Some(body_buffers) = envoy_filter.get_request_body() {
+            let mut body_data = Vec::new();
+            for buffer in body_buffers {
+                body_data.extend_from_slice(buffer.as_slice());
+            }
+            if let Ok(body_str) = std::str::from_utf8(&body_data) {
+                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(body_str) {
+                    if let Some(id) = parsed.get("id") {
+                        return id.to_string();
+                    }
+                }
+            }
+        }
+        "null".to_string()
+    }
+
     // Extract and store request body data for later modification
     fn extract_request_body<EHF: EnvoyHttpFilter>(&mut self, envoy_filter: &mut EHF) -> Option<String> {
         if let Some(body_buffers) = envoy_filter.get_request_body() {
@@ -104,7 +536,7 @@ impl Filter {
             for buffer in body_buffers {
                 body_data.extend_from_slice(buffer.as_slice());
             }
-            
+
             if let Ok(body_str) = std::str::from_utf8(&body_data) {
                 self.current_request_body = Some(body_str.to_string());
                 return Some(body_str.to_string());
@@ -112,15 +544,225 @@ impl Filter {
         }
         None
     }
+
+    // Resolves the backend session for `route` (via cache or gateway callout)
+    // and sets the routing headers. Shared by the singleton and batch request
+    // paths once each has rewritten the body and matched a single route.
+    // `tool_call_count` is how many `tools/call` this request represents (1
+    // for the singleton path, the batch length for the batch path), so the
+    // rate limiter charges one token per call rather than one per request.
+    fn resolve_and_route<EHF: EnvoyHttpFilter>(
+        &mut self,
+        envoy_filter: &mut EHF,
+        route: RouteConfig,
+        request_id: Option<serde_json::Value>,
+        stripped_tool_name: Option<String>,
+        tool_call_count: usize,
+    ) -> abi::envoy_dynamic_module_type_on_http_filter_request_body_status {
+        let route_to = route.backend.as_str();
+
+        // Get gateway session from headers
+        let headers = envoy_filter.get_request_headers();
+        let gateway_session = find_header_value(&headers, "mcp-session-id");
+
+        // Enforce the optional local rate limit here rather than in each
+        // caller, so a client can't defeat it by wrapping tools/call in a
+        // JSON-RPC batch: both the singleton and batch paths land here.
+        if let Some(rate_limit) = &self.rate_limit {
+            let key = match rate_limit.key_by {
+                RateLimitKeyBy::Session => gateway_session.clone(),
+                RateLimitKeyBy::Tool => stripped_tool_name.clone().unwrap_or_default(),
+            };
+
+            if !self.rate_limiter.try_acquire_n(&key, rate_limit.capacity, rate_limit.refill_per_sec, tool_call_count as f64) {
+                eprintln!("[MCP_FILTER] Rate limit exceeded for key: {} ({} tools/call)", key, tool_call_count);
+                self.send_local_reply(envoy_filter, 429, br#"{"error":"rate limit exceeded"}"#);
+                // A terminal local reply has already been sent; don't ask
+                // Envoy to keep buffering a body no one will read.
+                return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationNoBuffer;
+            }
+        }
+
+        if gateway_session.is_empty() {
+            eprintln!("[MCP_FILTER] No mcp-session-id header found");
+            return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue;
+        }
+
+        eprintln!("[MCP_FILTER] Gateway session: {}", gateway_session);
+
+        // Skip the callout entirely on a fresh cache hit for this gateway session.
+        if let Some(sessions) = self.session_cache.get(&gateway_session, self.session_cache_ttl_secs) {
+            if let Some(backend_session) = sessions.get(&route.backend) {
+                eprintln!("[MCP_FILTER] Session cache hit for gateway session: {}", gateway_session);
+                envoy_filter.set_request_header("mcp-session-id", backend_session.as_bytes());
+                envoy_filter.set_request_header("x-mcp-server", route_to.as_bytes());
+                envoy_filter.clear_route_cache();
+                return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue;
+            }
+        }
+
+        // Store the matched route for later use
+        self.pending_route = Some(route.clone());
+        self.pending_gateway_session = Some(gateway_session.clone());
+        self.pending_request_id = request_id;
+        self.stripped_tool_name = stripped_tool_name;
+
+        // Initiate HTTP callout to gateway for session lookup
+        eprintln!("[MCP_FILTER] Making HTTP callout to gateway for session: {}", gateway_session);
+
+        let request_id = self.request_id.clone().unwrap_or_default();
+        let headers = vec![
+            (":method", b"GET".as_slice()),
+            (":path", b"/session-lookup".as_slice()),
+            (":authority", b"gateway:8080".as_slice()),
+            ("content-length", b"0".as_slice()),
+            ("x-gateway-session-id", gateway_session.as_bytes()),
+            (self.request_id_header.as_str(), request_id.as_bytes()),
+        ];
+
+        let result = envoy_filter.send_http_callout(
+            1234,
+            "gateway_cluster",
+            headers,
+            Some(b""),
+            self.callout_timeout_ms
+        );
+
+        match result {
+            abi::envoy_dynamic_module_type_http_callout_init_result::Success => {
+                eprintln!("[MCP_FILTER] HTTP callout initiated successfully");
+                eprintln!("[MCP_FILTER] HTTP callout initiated for {} session lookup", route_to);
+                abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationAndBuffer
+            }
+            _ => {
+                eprintln!("[MCP_FILTER] Failed to initiate HTTP callout");
+
+                // Honor session_lookup_fail_mode here too: a strict-mode
+                // deployment must reject when the callout can't even be
+                // started, not just when it starts and later fails.
+                let rejected = self.apply_fallback_session(envoy_filter, &route, "gateway callout failed to initiate");
+
+                self.pending_route = None;
+                self.pending_gateway_session = None;
+                self.pending_request_id = None;
+                self.stripped_tool_name = None;
+
+                if rejected {
+                    abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationNoBuffer
+                } else {
+                    eprintln!("[MCP_FILTER] Routing decision: {}", route_to);
+                    abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue
+                }
+            }
+        }
+    }
+
+    // Handles a top-level JSON-RPC batch (an array of request objects).
+    // Routes the whole batch as a unit if every tools/call element resolves
+    // to the same backend; otherwise rejects it (or lets it through to the
+    // gateway unmodified, per `allow_mixed_batch_to_gateway`).
+    fn handle_batch_request<EHF: EnvoyHttpFilter>(
+        &mut self,
+        envoy_filter: &mut EHF,
+        elements: Vec<serde_json::Value>,
+    ) -> abi::envoy_dynamic_module_type_on_http_filter_request_body_status {
+        eprintln!("[MCP_FILTER] Body is a JSON-RPC batch of {} element(s)", elements.len());
+
+        let route = match resolve_batch_route(&self.routes, &elements) {
+            Some(route) => route,
+            None => {
+                if self.allow_mixed_batch_to_gateway {
+                    eprintln!("[MCP_FILTER] Batch isn't routable to a single backend, sending to gateway as-is");
+                    return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue;
+                }
+                eprintln!("[MCP_FILTER] Batch isn't routable to a single backend, rejecting");
+                let error_body = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {
+                        "code": -32600,
+                        "message": "cross-backend JSON-RPC batches are not supported",
+                    },
+                })
+                .to_string();
+                self.send_local_reply(envoy_filter, 400, error_body.as_bytes());
+                return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationNoBuffer;
+            }
+        };
+
+        // Strip each element's own matched route prefix and rewrite the
+        // whole array in place, same as the singleton path does for one.
+        // Elements can share a backend via different prefixes (e.g.
+        // "server1-" and "server1beta-" both routing to "server1"), so this
+        // must re-match per element rather than reuse the batch-level
+        // `route`'s prefix length for everyone.
+        let rewritten: Vec<serde_json::Value> = elements
+            .into_iter()
+            .map(|mut element| {
+                if let Some(name) = element.get("params").and_then(|p| p.get("name")).and_then(|n| n.as_str()) {
+                    if let Some(element_route) = match_route(&self.routes, name) {
+                        let stripped = name[element_route.prefix.len()..].to_string();
+                        if let Some(params_obj) = element.get_mut("params").and_then(|p| p.as_object_mut()) {
+                            params_obj.insert("name".to_string(), serde_json::Value::String(stripped));
+                        }
+                    }
+                }
+                element
+            })
+            .collect();
+
+        let batch_id = rewritten.first().and_then(|e| e.get("id")).cloned();
+        // Representative stripped tool name for per-tool rate limiting: the
+        // batch may carry several tool names, so key on the first element's.
+        let representative_tool_name = rewritten
+            .first()
+            .and_then(|e| e.get("params"))
+            .and_then(|p| p.get("name"))
+            .and_then(|n| n.as_str())
+            .map(|n| n.to_string());
+        let tool_call_count = rewritten.len();
+
+        if let Ok(modified_body) = serde_json::to_string(&serde_json::Value::Array(rewritten)) {
+            let new_body_bytes = modified_body.as_bytes();
+            if let Some(body_buffers) = envoy_filter.get_request_body() {
+                let current_body_size: usize = body_buffers.iter().map(|b| b.as_slice().len()).sum();
+                if envoy_filter.drain_request_body(current_body_size) {
+                    if envoy_filter.append_request_body(new_body_bytes) {
+                        let new_length = new_body_bytes.len().to_string();
+                        envoy_filter.set_request_header("content-length", new_length.as_bytes());
+                        eprintln!("[MCP_FILTER] Replaced batch request body with stripped tool names");
+                    } else {
+                        eprintln!("[MCP_FILTER] Failed to append rewritten batch body");
+                    }
+                } else {
+                    eprintln!("[MCP_FILTER] Failed to drain batch request body");
+                }
+            }
+            self.current_request_body = Some(modified_body);
+        }
+
+        self.resolve_and_route(envoy_filter, route, batch_id, representative_tool_name, tool_call_count)
+    }
 }
 
 impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
     fn on_request_headers(
         &mut self,
-        _envoy_filter: &mut EHF,
+        envoy_filter: &mut EHF,
         end_of_stream: bool,
     ) -> abi::envoy_dynamic_module_type_on_http_filter_request_headers_status {
-        
+        // Resolve the correlation id for this request: trust the inbound
+        // header unless configured to always mint a fresh one, and fall back
+        // to a UUIDv4 when it's absent.
+        let inbound = find_header_value(&envoy_filter.get_request_headers(), &self.request_id_header);
+        let request_id = if !self.always_regenerate_request_id && !inbound.is_empty() {
+            inbound
+        } else {
+            Uuid::new_v4().to_string()
+        };
+        envoy_filter.set_request_header(&self.request_id_header, request_id.as_bytes());
+        self.request_id = Some(request_id);
+
         // CRITICAL: For requests with bodies, we must pause header processing here.
         // If we don't pause, Envoy will make routing decisions before we can analyze
         // the body content and set our routing header. StopIteration prevents
@@ -128,14 +770,34 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
         if !end_of_stream {
             return abi::envoy_dynamic_module_type_on_http_filter_request_headers_status::StopIteration;
         }
-        
+
         // No body expected - continue with default routing
         abi::envoy_dynamic_module_type_on_http_filter_request_headers_status::Continue
     }
 
     fn on_request_body(&mut self, envoy_filter: &mut EHF, end_of_stream: bool) -> abi::envoy_dynamic_module_type_on_http_filter_request_body_status {
         eprintln!("[MCP_FILTER] Body received (end_of_stream={})", end_of_stream);
-        
+
+        if let Some(max_bytes) = self.max_body_bytes {
+            let buffered_size: u64 = envoy_filter
+                .get_request_body()
+                .map(|bufs| bufs.iter().map(|b| b.as_slice().len() as u64).sum())
+                .unwrap_or(0);
+
+            if buffered_size > max_bytes {
+                eprintln!("[MCP_FILTER] Buffered body size {} exceeds max_body_bytes {}, rejecting", buffered_size, max_bytes);
+                let id = self.extract_partial_request_id(envoy_filter);
+                let error_body = format!(
+                    r#"{{"jsonrpc":"2.0","id":{},"error":{{"code":-32600,"message":"request too large"}}}}"#,
+                    id
+                );
+                self.send_local_reply(envoy_filter, 413, error_body.as_bytes());
+                // A terminal local reply has already been sent; don't ask
+                // Envoy to keep buffering the rest of an oversized body.
+                return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationNoBuffer;
+            }
+        }
+
         if !end_of_stream {
             return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationAndBuffer;
         }
@@ -160,6 +822,13 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
             }
         };
 
+        // A JSON-RPC batch is a top-level array of request objects rather
+        // than a single object; route it as a unit instead of assuming one
+        // method/params pair.
+        if let serde_json::Value::Array(elements) = &parsed {
+            return self.handle_batch_request(envoy_filter, elements.clone());
+        }
+
         let method = parsed.get("method")
             .and_then(|m| m.as_str())
             .unwrap_or("");
@@ -180,15 +849,16 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
 
         eprintln!("[MCP_FILTER] Tool name: {}", tool_name);
 
-        // Determine routing based on tool prefix
-        let (route_to, stripped_tool_name) = if tool_name.starts_with("server1-") {
-            ("server1", &tool_name[8..]) // Strip "server1-" prefix
-        } else if tool_name.starts_with("server2-") {
-            ("server2", &tool_name[8..]) // Strip "server2-" prefix  
-        } else {
-            eprintln!("[MCP_FILTER] Tool name doesn't start with server1- or server2-, continuing to gateway");
-            return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue;
+        // Determine routing based on the longest matching configured prefix
+        let route = match match_route(&self.routes, tool_name) {
+            Some(route) => route.clone(),
+            None => {
+                eprintln!("[MCP_FILTER] Tool name doesn't match any configured route prefix, continuing to gateway");
+                return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue;
+            }
         };
+        let stripped_tool_name = &tool_name[route.prefix.len()..];
+        let route_to = route.backend.as_str();
 
         eprintln!("[MCP_FILTER] Routing to: {}, stripped tool name: {}", route_to, stripped_tool_name);
 
@@ -201,17 +871,17 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
 
                     if let Ok(modified_body) = serde_json::to_string(&json_value) {
                         let new_body_bytes = modified_body.as_bytes();
-                        
+
                         // Replace the entire request body using Envoy API
                         if let Some(body_buffers) = envoy_filter.get_request_body() {
                             let current_body_size: usize = body_buffers.iter().map(|b| b.as_slice().len()).sum();
-                            
+
                             if envoy_filter.drain_request_body(current_body_size) {
                                 if envoy_filter.append_request_body(new_body_bytes) {
                                     // Update content-length header
                                     let new_length = new_body_bytes.len().to_string();
                                     envoy_filter.set_request_header("content-length", new_length.as_bytes());
-                                    
+
                                     eprintln!("[MCP_FILTER] ✅ Successfully replaced request body with stripped tool name: {}", stripped_tool_name);
                                 } else {
                                     eprintln!("[MCP_FILTER] ❌ Failed to append new request body");
@@ -220,7 +890,7 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
                                 eprintln!("[MCP_FILTER] ❌ Failed to drain request body");
                             }
                         }
-                        
+
                         // Store the modified body for later use
                         self.current_request_body = Some(modified_body);
                     }
@@ -228,89 +898,45 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
             }
         }
 
-        // Get gateway session from headers
-        let headers = envoy_filter.get_request_headers();
-        let gateway_session = find_header_value(&headers, "mcp-session-id");
-
-        if gateway_session.is_empty() {
-            eprintln!("[MCP_FILTER] No mcp-session-id header found");
-            return abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue;
-        }
-
-        eprintln!("[MCP_FILTER] Gateway session: {}", gateway_session);
-
-        // Store routing decision and stripped tool name for later use
-        self.pending_route_decision = Some(route_to.to_string());
-        self.stripped_tool_name = Some(stripped_tool_name.to_string());
-
-        // Initiate HTTP callout to gateway for session lookup
-        eprintln!("[MCP_FILTER] Making HTTP callout to gateway for session: {}", gateway_session);
-
-        let headers = vec![
-            (":method", b"GET".as_slice()),
-            (":path", b"/session-lookup".as_slice()),
-            (":authority", b"gateway:8080".as_slice()),
-            ("content-length", b"0".as_slice()),
-            ("x-gateway-session-id", gateway_session.as_bytes()),
-        ];
-
-        let result = envoy_filter.send_http_callout(
-            1234,
-            "gateway_cluster",
-            headers,
-            Some(b""),
-            5000
-        );
-
-        match result {
-            abi::envoy_dynamic_module_type_http_callout_init_result::Success => {
-                eprintln!("[MCP_FILTER] HTTP callout initiated successfully");
-                eprintln!("[MCP_FILTER] HTTP callout initiated for {} session lookup", route_to);
-                abi::envoy_dynamic_module_type_on_http_filter_request_body_status::StopIterationAndBuffer
-            }
-            _ => {
-                eprintln!("[MCP_FILTER] Failed to initiate HTTP callout");
-                eprintln!("[MCP_FILTER] HTTP callout failed, using placeholder");
-                
-                // Fallback to placeholder session
-                let backend_session = format!("{}-session-{}", route_to, gateway_session);
-                eprintln!("[MCP_FILTER] Mapping to {} session: {}", route_to, backend_session);
-                
-                // Set headers and continue
-                envoy_filter.set_request_header("mcp-session-id", backend_session.as_bytes());
-                envoy_filter.set_request_header("x-mcp-server", route_to.as_bytes());
-                envoy_filter.clear_route_cache();
-                
-                eprintln!("[MCP_FILTER] Routing decision: {}", route_to);
-                abi::envoy_dynamic_module_type_on_http_filter_request_body_status::Continue
-            }
-        }
+        self.resolve_and_route(envoy_filter, route, parsed.get("id").cloned(), Some(stripped_tool_name.to_string()), 1)
     }
 
     fn on_response_headers(&mut self, envoy_filter: &mut EHF, end_of_stream: bool) -> abi::envoy_dynamic_module_type_on_http_filter_response_headers_status {
         eprintln!("[MCP_FILTER] Response headers received (end_of_stream={})", end_of_stream);
-        
+
+        // Echo the correlation id back so the client can tie the response to
+        // the request it sent, regardless of which backend served it.
+        if let Some(request_id) = &self.request_id {
+            envoy_filter.set_response_header(&self.request_id_header, request_id.as_bytes());
+        }
+
         // Check if we have a backend session ID that needs to be mapped back
         let headers = envoy_filter.get_response_headers();
         let backend_session_id = find_header_value(&headers, "mcp-session-id");
-        
+
         if !backend_session_id.is_empty() {
             eprintln!("[MCP_FILTER] Response backend session: {}", backend_session_id);
-            
-            // Check if this is a server1 or server2 session that needs mapping back to gateway session
-            if backend_session_id.starts_with("server1-session-") || backend_session_id.starts_with("server2-session-") {
+
+            // Check if this is a session from one of the configured routes that
+            // needs mapping back to the gateway session. Longest-prefix match,
+            // symmetric with match_route on the request side: with overlapping
+            // session_prefix values, the first match isn't necessarily the
+            // right one.
+            let matched_route = self
+                .routes
+                .iter()
+                .filter(|route| backend_session_id.starts_with(route.session_prefix.as_str()))
+                .max_by_key(|route| route.session_prefix.len());
+
+            if let Some(route) = matched_route {
                 // Extract the original gateway session ID by removing the prefix
-                let gateway_session = if backend_session_id.starts_with("server1-session-") {
-                    &backend_session_id[16..] // Remove "server1-session-" prefix
-                } else {
-                    &backend_session_id[16..] // Remove "server2-session-" prefix  
-                };
-                
+                let gateway_session = &backend_session_id[route.session_prefix.len()..];
+
                 eprintln!("[MCP_FILTER] Mapping backend session back to gateway session: {}", gateway_session);
                 envoy_filter.set_response_header("mcp-session-id", gateway_session.as_bytes());
             }
         }
-        
+
         abi::envoy_dynamic_module_type_on_http_filter_response_headers_status::Continue
     }
 
@@ -323,11 +949,11 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
         body: Option<&[EnvoyBuffer]>,
     ) {
         eprintln!("[MCP_FILTER] HTTP callout {} completed with result: {:?}", callout_id, result);
-        
-        // Clone route_decision to avoid borrowing issues
-        let route_decision = self.pending_route_decision.clone();
-        
-        if let Some(route_decision_str) = route_decision {
+
+        // Clone the pending route to avoid borrowing issues
+        let route = self.pending_route.clone();
+
+        if let Some(route) = route {
             match result {
                 abi::envoy_dynamic_module_type_http_callout_result::Success => {
                     if let Some(body_buffers) = body {
@@ -335,12 +961,12 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
                         for buffer in body_buffers {
                             response_data.extend_from_slice(buffer.as_slice());
                         }
-                        
+
                         let response_str = match std::str::from_utf8(&response_data) {
                             Ok(s) => s,
                             Err(_) => {
                                 eprintln!("[MCP_FILTER] Failed to parse HTTP callout response as UTF-8");
-                                self.handle_fallback_session(envoy_filter, &route_decision_str);
+                                self.handle_fallback_session(envoy_filter, &route, "gateway response was not valid UTF-8");
                                 return;
                             }
                         };
@@ -349,36 +975,76 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
                         eprintln!("[MCP_FILTER] Response body: {}", response_str);
 
                         // Parse the JSON response
-                        let parsed: SessionLookupResponse = match serde_json::from_str(response_str) {
+                        let mut parsed: SessionLookupResponse = match serde_json::from_str(response_str) {
                             Ok(resp) => resp,
                             Err(e) => {
                                 eprintln!("[MCP_FILTER] Failed to parse session lookup response: {}", e);
-                                self.handle_fallback_session(envoy_filter, &route_decision_str);
+                                self.handle_fallback_session(envoy_filter, &route, "gateway response was not valid JSON");
                                 return;
                             }
                         };
+                        // Fold in server1_session_id/server2_session_id if the gateway is
+                        // still speaking the pre-routing-table wire format, so un-upgraded
+                        // deployments keep working.
+                        parsed.normalize_legacy_fields();
 
                         if !parsed.found {
                             eprintln!("[MCP_FILTER] Session mapping not found, using fallback");
-                            self.handle_fallback_session(envoy_filter, &route_decision_str);
+                            self.handle_fallback_session(envoy_filter, &route, "gateway reported no session mapping for this client");
+                            return;
+                        }
+
+                        // `found: true` with an empty `sessions` map (after folding in the
+                        // legacy flat fields above) means the gateway's response matches
+                        // none of the wire shapes this filter understands at all. That's a
+                        // genuine contract mismatch, not an ordinary miss, so reject loudly
+                        // regardless of session_lookup_fail_mode rather than routing to a
+                        // fabricated session every request would otherwise silently fall
+                        // back to.
+                        if parsed.sessions.is_empty() {
+                            eprintln!("[MCP_FILTER] Gateway reported found=true but no sessions in any known shape, rejecting");
+                            let id = self.pending_request_id.take().unwrap_or(serde_json::Value::Null);
+                            let error_body = serde_json::json!({
+                                "jsonrpc": "2.0",
+                                "id": id,
+                                "error": {
+                                    "code": -32000,
+                                    "message": "gateway session lookup returned an empty sessions map; check the gateway emits the routes-based wire format",
+                                },
+                            })
+                            .to_string();
+                            self.send_local_reply(envoy_filter, 502, error_body.as_bytes());
+                            self.pending_route = None;
+                            self.pending_gateway_session = None;
+                            self.pending_request_id = None;
+                            self.stripped_tool_name = None;
                             return;
                         }
 
-                        // Use the correct session ID based on routing decision
-                        let backend_session = if route_decision_str == "server1" {
-                            parsed.server1_session_id
-                        } else {
-                            parsed.server2_session_id
+                        // Cache the resolved mapping so the next tools/call for this
+                        // gateway session can skip the callout entirely.
+                        if let Some(gateway_session) = self.pending_gateway_session.clone() {
+                            self.session_cache.insert(gateway_session, parsed.sessions.clone());
+                        }
+
+                        // Use the session ID the gateway resolved for this route's backend
+                        let backend_session = match parsed.sessions.get(&route.backend) {
+                            Some(session_id) => session_id.clone(),
+                            None => {
+                                eprintln!("[MCP_FILTER] Gateway response has no session for backend {}, using fallback", route.backend);
+                                self.handle_fallback_session(envoy_filter, &route, &format!("gateway response had no session for backend '{}'", route.backend));
+                                return;
+                            }
                         };
 
                         eprintln!("[MCP_FILTER] Using gateway-provided session: {}", backend_session);
 
                         // Set the correct session header
                         envoy_filter.set_request_header("mcp-session-id", backend_session.as_bytes());
-                        
+
                         // Set routing header
-                        envoy_filter.set_request_header("x-mcp-server", route_decision_str.as_bytes());
-                        eprintln!("[MCP_FILTER] Setting routing header: {}", route_decision_str);
+                        envoy_filter.set_request_header("x-mcp-server", route.backend.as_bytes());
+                        eprintln!("[MCP_FILTER] Setting routing header: {}", route.backend);
 
                         // Clear route cache and continue
                         envoy_filter.clear_route_cache();
@@ -386,18 +1052,113 @@ impl<EHF: EnvoyHttpFilter> HttpFilter<EHF> for Filter {
                         envoy_filter.continue_decoding();
 
                         // Clear the pending state
-                        self.pending_route_decision = None;
+                        self.pending_route = None;
+                        self.pending_gateway_session = None;
+                        self.pending_request_id = None;
                         self.stripped_tool_name = None;
                     } else {
                         eprintln!("[MCP_FILTER] No response body, using fallback");
-                        self.handle_fallback_session(envoy_filter, &route_decision_str);
+                        self.handle_fallback_session(envoy_filter, &route, "gateway returned no response body");
                     }
                 }
                 _ => {
                     eprintln!("[MCP_FILTER] HTTP callout failed, using fallback session");
-                    self.handle_fallback_session(envoy_filter, &route_decision_str);
+                    self.handle_fallback_session(envoy_filter, &route, "gateway callout timed out or failed");
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, backend: &str, session_prefix: &str) -> RouteConfig {
+        RouteConfig {
+            prefix: prefix.to_string(),
+            backend: backend.to_string(),
+            session_prefix: session_prefix.to_string(),
+        }
+    }
+
+    fn tools_call(tool_name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "tools/call",
+            "params": { "name": tool_name },
+        })
+    }
+
+    #[test]
+    fn match_route_prefers_longest_prefix() {
+        let routes = vec![
+            route("server1-", "server1", "server1-session-"),
+            route("server1beta-", "server1beta", "server1beta-session-"),
+        ];
+
+        let matched = match_route(&routes, "server1beta-do-thing").expect("should match");
+        assert_eq!(matched.backend, "server1beta");
+    }
+
+    #[test]
+    fn match_route_no_match_returns_none() {
+        let routes = default_routes();
+        assert!(match_route(&routes, "unrouted-tool").is_none());
+    }
+
+    #[test]
+    fn resolve_batch_route_same_backend_is_routable() {
+        let routes = default_routes();
+        let elements = vec![tools_call("server1-a"), tools_call("server1-b")];
+
+        let route = resolve_batch_route(&routes, &elements).expect("should be routable");
+        assert_eq!(route.backend, "server1");
+    }
+
+    #[test]
+    fn resolve_batch_route_mixed_backends_is_not_routable() {
+        let routes = default_routes();
+        let elements = vec![tools_call("server1-a"), tools_call("server2-b")];
+
+        assert!(resolve_batch_route(&routes, &elements).is_none());
+    }
+
+    #[test]
+    fn resolve_batch_route_non_tools_call_is_not_routable() {
+        let routes = default_routes();
+        let elements = vec![
+            tools_call("server1-a"),
+            serde_json::json!({ "jsonrpc": "2.0", "id": 2, "method": "tools/list" }),
+        ];
+
+        assert!(resolve_batch_route(&routes, &elements).is_none());
+    }
+
+    #[test]
+    fn session_cache_hit_within_ttl() {
+        let cache = SessionCache::new();
+        let mut sessions = HashMap::new();
+        sessions.insert("server1".to_string(), "server1-session-abc".to_string());
+        cache.insert("gw-session".to_string(), sessions.clone());
+
+        assert_eq!(cache.get("gw-session", 300), Some(sessions));
+    }
+
+    #[test]
+    fn session_cache_expires_immediately_at_zero_ttl() {
+        let cache = SessionCache::new();
+        let mut sessions = HashMap::new();
+        sessions.insert("server1".to_string(), "server1-session-abc".to_string());
+        cache.insert("gw-session".to_string(), sessions);
+
+        assert_eq!(cache.get("gw-session", 0), None);
+    }
+
+    #[test]
+    fn session_cache_miss_for_unknown_session() {
+        let cache = SessionCache::new();
+        assert_eq!(cache.get("nonexistent", 300), None);
+    }
+}